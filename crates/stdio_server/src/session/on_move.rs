@@ -4,6 +4,7 @@ use log::{debug, error};
 use pattern::*;
 use std::path::Path;
 use std::path::PathBuf;
+use tree_sitter::{Language, Parser, Query, QueryCursor};
 
 #[inline]
 fn as_absolute_path<P: AsRef<Path>>(path: P) -> Result<String> {
@@ -13,6 +14,129 @@ fn as_absolute_path<P: AsRef<Path>>(path: P) -> Result<String> {
         .map_err(|e| anyhow!("{:?}, path:{}", e, path.as_ref().display()))
 }
 
+/// One highlighted token within a preview line: a byte range relative to
+/// the start of that line, together with the tree-sitter capture name
+/// (e.g. `"keyword"`, `"function"`, `"string"`) Vim maps to a color.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HighlightSpan {
+    pub col_start: usize,
+    pub col_end: usize,
+    pub capture: String,
+}
+
+/// Per-line highlight spans, aligned 1:1 with the content lines they were
+/// computed from (i.e. not counting the leading `fname` line).
+pub type LineHighlights = Vec<Vec<HighlightSpan>>;
+
+/// Returns the tree-sitter `Language` and its bundled highlight query for a
+/// file extension, or `None` when no grammar is available, so previews for
+/// an unrecognized language simply degrade to plain lines.
+fn language_for_extension(ext: &str) -> Option<(Language, &'static str)> {
+    Some(match ext {
+        "rs" => (
+            tree_sitter_rust::language(),
+            tree_sitter_rust::HIGHLIGHT_QUERY,
+        ),
+        "py" => (
+            tree_sitter_python::language(),
+            tree_sitter_python::HIGHLIGHT_QUERY,
+        ),
+        "js" | "jsx" => (
+            tree_sitter_javascript::language(),
+            tree_sitter_javascript::HIGHLIGHT_QUERY,
+        ),
+        "go" => (tree_sitter_go::language(), tree_sitter_go::HIGHLIGHT_QUERY),
+        _ => return None,
+    })
+}
+
+/// Offsets of the start of each line in `source`, `source[0]` included.
+fn line_start_offsets(source: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
+}
+
+/// Returns the index of the line containing `byte_offset`, i.e. the
+/// largest `i` such that `line_starts[i] <= byte_offset`.
+fn line_index_for(line_starts: &[usize], byte_offset: usize) -> usize {
+    match line_starts.binary_search(&byte_offset) {
+        Ok(i) => i,
+        Err(i) => i.saturating_sub(1),
+    }
+}
+
+/// Parses `source`, the text of the lines currently shown in the preview
+/// window, using the tree-sitter grammar detected from `path`'s extension,
+/// and returns per-line highlight spans. Returns `None` when the language
+/// of `path` has no registered grammar.
+fn highlight_source(path: &Path, source: &str) -> Option<LineHighlights> {
+    let ext = path.extension()?.to_str()?;
+    let (language, highlight_query) = language_for_extension(ext)?;
+
+    let mut parser = Parser::new();
+    parser.set_language(language).ok()?;
+    let tree = parser.parse(source, None)?;
+
+    let query = Query::new(language, highlight_query).ok()?;
+    let mut cursor = QueryCursor::new();
+
+    let line_starts = line_start_offsets(source);
+    let mut lines: LineHighlights = vec![Vec::new(); line_starts.len()];
+
+    // TODO: captures aren't de-duplicated or priority-resolved, so a node
+    // matched by more than one pattern in `highlight_query` (e.g. a generic
+    // `@variable` and a more specific `@variable.parameter`) currently
+    // produces overlapping spans for the same range. The real
+    // `tree-sitter-highlight` crate resolves this with a priority stack;
+    // we don't do that here yet.
+    for m in cursor.matches(&query, tree.root_node(), source.as_bytes()) {
+        for capture in m.captures {
+            let node = capture.node;
+            let start_byte = node.start_byte();
+            let end_byte = node.end_byte();
+            // `end_byte` is exclusive; the node's last real byte is the one
+            // before it, which is what determines its last line.
+            let last_byte = end_byte.saturating_sub(1).max(start_byte);
+
+            let start_line = line_index_for(&line_starts, start_byte);
+            let end_line = line_index_for(&line_starts, last_byte);
+            let capture_name = query.capture_names()[capture.index as usize].clone();
+
+            // A node spanning multiple lines (a block comment, a
+            // triple-quoted docstring, a template literal, ...) needs one
+            // span per line it crosses, not just a span on its first line.
+            for line_idx in start_line..=end_line {
+                let line_start = line_starts[line_idx];
+                // Exclude the trailing `\n` itself from the line's content
+                // range, it's not part of any line's displayed text.
+                let line_end = line_starts
+                    .get(line_idx + 1)
+                    .map_or(source.len(), |next| next - 1);
+
+                let col_start = if line_idx == start_line {
+                    start_byte - line_start
+                } else {
+                    0
+                };
+                let col_end = if line_idx == end_line {
+                    end_byte.min(line_end) - line_start
+                } else {
+                    line_end - line_start
+                };
+
+                lines[line_idx].push(HighlightSpan {
+                    col_start,
+                    col_end,
+                    capture: capture_name.clone(),
+                });
+            }
+        }
+    }
+
+    Some(lines)
+}
+
 /// Preview environment on Vim CursorMoved event.
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
@@ -129,8 +253,10 @@ impl OnMoveHandler {
         match utility::read_preview_lines(path.as_ref(), lnum, self.size) {
             Ok((lines_iter, hi_lnum)) => {
                 let fname = format!("{}", path.as_ref().display());
+                let content_lines = lines_iter.collect::<Vec<_>>();
+                let highlights = highlight_source(path.as_ref(), &content_lines.join("\n"));
                 let lines = std::iter::once(fname.clone())
-                    .chain(lines_iter)
+                    .chain(content_lines)
                     .collect::<Vec<_>>();
                 debug!(
                     "sending msg_id:{}, provider_id:{}",
@@ -140,7 +266,8 @@ impl OnMoveHandler {
                   "event": "on_move",
                   "lines": lines,
                   "fname": fname,
-                  "hi_lnum": hi_lnum
+                  "hi_lnum": hi_lnum,
+                  "highlights": highlights
                 }));
             }
             Err(err) => {
@@ -156,14 +283,17 @@ impl OnMoveHandler {
 
     fn preview_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let abs_path = as_absolute_path(path.as_ref())?;
-        let lines_iter = utility::read_first_lines(path.as_ref(), 2 * self.size)?;
+        let content_lines =
+            utility::read_first_lines(path.as_ref(), 2 * self.size)?.collect::<Vec<_>>();
+        let highlights = highlight_source(path.as_ref(), &content_lines.join("\n"));
         let lines = std::iter::once(abs_path.clone())
-            .chain(lines_iter)
+            .chain(content_lines)
             .collect::<Vec<_>>();
         self.send_response(json!({
           "event": "on_move",
           "lines": lines,
-          "fname": abs_path
+          "fname": abs_path,
+          "highlights": highlights
         }));
         Ok(())
     }
@@ -178,4 +308,47 @@ impl OnMoveHandler {
         }));
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_line_start_offsets() {
+    assert_eq!(line_start_offsets("abc"), vec![0]);
+    assert_eq!(line_start_offsets("abc\ndef"), vec![0, 4]);
+    assert_eq!(line_start_offsets("a\nb\nc"), vec![0, 2, 4]);
+}
+
+#[test]
+fn test_highlight_source_unknown_extension_returns_none() {
+    let path = PathBuf::from("foo.unknownlang");
+    assert!(highlight_source(&path, "whatever").is_none());
+}
+
+#[test]
+fn test_highlight_source_single_line_rust() {
+    let path = PathBuf::from("foo.rs");
+    let source = "fn main() {}";
+    let highlights = highlight_source(&path, source).expect("rust grammar available");
+    assert_eq!(highlights.len(), 1);
+    assert!(!highlights[0].is_empty());
+}
+
+#[test]
+fn test_highlight_source_multiline_comment_spans_every_line_it_crosses() {
+    let path = PathBuf::from("foo.rs");
+    let source = "/* line one\n   line two */\nfn main() {}";
+    let highlights = highlight_source(&path, source).expect("rust grammar available");
+    assert_eq!(highlights.len(), 3);
+    // The block comment covers both of its lines, not just the first one.
+    assert!(!highlights[0].is_empty());
+    assert!(!highlights[1].is_empty());
+}
+
+#[test]
+fn test_highlight_source_python_docstring_spans_every_line() {
+    let path = PathBuf::from("foo.py");
+    let source = "\"\"\"\nsummary\ndetails\n\"\"\"\n";
+    let highlights = highlight_source(&path, source).expect("python grammar available");
+    for line in &highlights[..4] {
+        assert!(!line.is_empty(), "every docstring line should be highlighted");
+    }
+}
@@ -1,10 +1,16 @@
 #![feature(pattern)]
 
 use filter::matcher::{get_appropriate_matcher, Algo};
+use once_cell::sync::Lazy;
 use printer::truncate_long_matched_lines;
 use pyo3::prelude::*;
 use pyo3::wrap_pyfunction;
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use std::str::pattern::Pattern;
 
@@ -13,6 +19,40 @@ fn find_start_at<'a, P: Pattern<'a>>(slice: &'a str, at: usize, pat: P) -> Optio
     slice[at..].find(pat).map(|i| at + i)
 }
 
+/// A single space-separated term of an extended query, together with the
+/// fzf-style modifier that controls how it is matched against the haystack.
+///
+/// Grammar (applied per whitespace-separated term):
+/// - `'term`  forces an exact substring match (the default is already a
+///   plain substring match, so this is mostly for fzf muscle-memory).
+/// - `^term`  anchors the term to the start of the haystack.
+/// - `term$`  anchors the term to the end of the haystack.
+/// - `!term`  negates the term: the candidate is rejected if it is present.
+enum QueryTerm<'a> {
+    Substring(&'a str),
+    AnchorStart(&'a str),
+    AnchorEnd(&'a str),
+    Negated(&'a str),
+}
+
+impl<'a> QueryTerm<'a> {
+    fn parse(term: &'a str) -> Self {
+        if let Some(rest) = term.strip_prefix('!') {
+            return Self::Negated(rest);
+        }
+        if let Some(rest) = term.strip_prefix('\'') {
+            return Self::Substring(rest);
+        }
+        if let Some(rest) = term.strip_prefix('^') {
+            return Self::AnchorStart(rest);
+        }
+        if let Some(rest) = term.strip_suffix('$') {
+            return Self::AnchorEnd(rest);
+        }
+        Self::Substring(term)
+    }
+}
+
 fn substr_scorer(niddle: &str, haystack: &str) -> Option<(f64, Vec<usize>)> {
     let haystack = haystack.to_lowercase();
     let haystack = haystack.as_str();
@@ -20,25 +60,61 @@ fn substr_scorer(niddle: &str, haystack: &str) -> Option<(f64, Vec<usize>)> {
     let mut offset = 0;
     let mut positions = Vec::new();
     for sub_niddle in niddle.split_whitespace() {
-        let sub_niddle = sub_niddle.to_lowercase();
+        let term = QueryTerm::parse(sub_niddle);
 
-        match find_start_at(haystack, offset, &sub_niddle) {
-            Some(idx) => {
+        match term {
+            QueryTerm::Negated(sub_niddle) => {
+                let sub_niddle = sub_niddle.to_lowercase();
+                if !sub_niddle.is_empty() && haystack.contains(&sub_niddle) {
+                    return None;
+                }
+            }
+            QueryTerm::AnchorStart(sub_niddle) => {
+                let sub_niddle = sub_niddle.to_lowercase();
+                if !haystack.starts_with(&sub_niddle) {
+                    return None;
+                }
+                let idx = 0;
+                if idx < offset {
+                    return None;
+                }
                 offset = idx + sub_niddle.len();
-                // For build without overflow checks this could be written as
-                // `let mut pos = idx - 1;` with `|| { pos += 1; pos }` closure.
-                let mut pos = idx;
-                positions.resize_with(
-                    positions.len() + sub_niddle.len(),
-                    // Simple endless iterator for `idx..` range. Even though it's endless,
-                    // it will iterate only `sub_niddle.len()` times.
-                    || {
-                        pos += 1;
-                        pos - 1
-                    },
-                );
+                positions.extend(idx..offset);
+            }
+            QueryTerm::AnchorEnd(sub_niddle) => {
+                let sub_niddle = sub_niddle.to_lowercase();
+                if !haystack.ends_with(&sub_niddle) {
+                    return None;
+                }
+                let idx = haystack.len() - sub_niddle.len();
+                if idx < offset {
+                    return None;
+                }
+                offset = haystack.len();
+                positions.extend(idx..offset);
+            }
+            QueryTerm::Substring(sub_niddle) => {
+                let sub_niddle = sub_niddle.to_lowercase();
+
+                match find_start_at(haystack, offset, &sub_niddle) {
+                    Some(idx) => {
+                        offset = idx + sub_niddle.len();
+                        // For build without overflow checks this could be written as
+                        // `let mut pos = idx - 1;` with `|| { pos += 1; pos }` closure.
+                        let mut pos = idx;
+                        positions.resize_with(
+                            positions.len() + sub_niddle.len(),
+                            // Simple endless iterator for `idx..` range. Even though it's endless,
+                            // it will iterate only `sub_niddle.len()` times.
+                            || {
+                                pos += 1;
+                                pos - 1
+                            },
+                        );
+                    }
+                    None => return None,
+                }
             }
-            None => return None,
         }
     }
 
@@ -66,6 +142,176 @@ type MatchedIndicesInBatch = Vec<Vec<usize>>;
 /// therefore hereby has to use HashMap<String, String> instead.
 type TruncatedMapInfo = HashMap<String, String>;
 
+/// Bonus added to the fzy score when the matched positions lie within the
+/// basename segment of a path, so e.g. `conf` ranks `src/config.rs` above
+/// `src/conf/util.rs`.
+const BASENAME_BONUS: f64 = 10f64;
+
+/// Returns the byte offset of the basename (the part after the last path
+/// separator) within `path`, or `0` if `path` has no separator.
+#[inline]
+fn basename_offset(path: &str) -> usize {
+    path.rfind(std::path::is_separator).map_or(0, |i| i + 1)
+}
+
+/// Runs `fzy_matcher` against both the full path and its basename, favoring
+/// a basename match with a bonus while still reporting indices relative to
+/// the full, unsliced `line` so Vim highlighting stays correct.
+fn path_aware_fzy_match(
+    fzy_matcher: &dyn Fn(&str, &str) -> Option<(i64, Vec<usize>)>,
+    line: &str,
+    query: &str,
+) -> MatcherResult {
+    let base = basename_offset(line);
+
+    let basename_match = if base > 0 {
+        fzy_matcher(&line[base..], query).map(|(score, indices)| {
+            (
+                score as f64 + BASENAME_BONUS,
+                indices.into_iter().map(|i| i + base).collect::<Vec<_>>(),
+            )
+        })
+    } else {
+        None
+    };
+
+    let full_match = fzy_matcher(line, query).map(|(score, indices)| (score as f64, indices));
+
+    combine_basename_bonus(basename_match, full_match)
+}
+
+/// Runs `substr_scorer` against both the full path and its basename, the
+/// extended-query-grammar counterpart to [`path_aware_fzy_match`] used when
+/// `query` contains space-separated terms or fzf-style modifiers.
+fn path_aware_substr_match(query: &str, line: &str) -> MatcherResult {
+    // The full path must match on its own, so a negated term found in the
+    // directory part, or a `^` term that fails at the start of the path,
+    // still rejects the candidate.
+    let full_match = substr_scorer(query, line)?;
+    let base = basename_offset(line);
+
+    let basename_match = if base > 0 {
+        substr_scorer(query, &line[base..]).map(|(score, indices)| {
+            (
+                score + BASENAME_BONUS,
+                indices.into_iter().map(|i| i + base).collect::<Vec<_>>(),
+            )
+        })
+    } else {
+        None
+    };
+
+    combine_basename_bonus(basename_match, Some(full_match))
+}
+
+/// Picks the higher-scoring of a basename match (already carrying its
+/// bonus) and a full-path match, preferring the basename match on a tie.
+fn combine_basename_bonus(
+    basename_match: MatcherResult,
+    full_match: MatcherResult,
+) -> MatcherResult {
+    match (basename_match, full_match) {
+        (Some(b), Some(f)) => Some(if b.0 >= f.0 { b } else { f }),
+        (Some(b), None) => Some(b),
+        (None, Some(f)) => Some(f),
+        (None, None) => None,
+    }
+}
+
+/// One scored candidate, ordered solely by its score so it can live in a
+/// `BinaryHeap` used as a bounded top-N selector.
+struct ScoredLine {
+    score: f64,
+    line: String,
+    indices: Vec<usize>,
+}
+
+impl PartialEq for ScoredLine {
+    fn eq(&self, other: &Self) -> bool {
+        self.score == other.score
+    }
+}
+impl Eq for ScoredLine {}
+impl PartialOrd for ScoredLine {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for ScoredLine {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.score.partial_cmp(&other.score).unwrap()
+    }
+}
+
+/// Pushes `item` into a min-heap bounded to `max_results` entries (`0` means
+/// unbounded), evicting the lowest-scored entry when the heap is already full
+/// and the new item scores higher.
+fn push_bounded(heap: &mut BinaryHeap<Reverse<ScoredLine>>, item: ScoredLine, max_results: usize) {
+    if max_results == 0 || heap.len() < max_results {
+        heap.push(Reverse(item));
+    } else if let Some(Reverse(min)) = heap.peek() {
+        if item.score > min.score {
+            heap.pop();
+            heap.push(Reverse(item));
+        }
+    }
+}
+
+/// Per-session cancellation state. `generation` is bumped monotonically (via
+/// `fetch_max`, never overwritten) so that out-of-order completion of two
+/// concurrent `fuzzy_match` calls can never rewind it back to an older
+/// value; `cancelled` is a separate flag so an explicit `cancel_match` can
+/// abort the in-flight call without that sentinel getting stuck forever and
+/// blocking every later query on the same session.
+struct SessionState {
+    generation: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+/// Tracks, per provider session, the generation of the most recently
+/// started `fuzzy_match` call. A matcher still running an older generation
+/// notices the mismatch and stops doing further work.
+///
+/// `stdio_server` is a long-lived process, so this map is bounded rather
+/// than left to grow for every `session_id` ever seen: `cancel_match`
+/// evicts its entry once it has nothing left to cancel, and `session_state`
+/// drops the whole map if it somehow still grows past `MAX_SESSIONS` (e.g. a
+/// session that was never explicitly cancelled).
+static SESSIONS: Lazy<Mutex<HashMap<u64, Arc<SessionState>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Upper bound on how many distinct sessions stay tracked at once. Sessions
+/// are short-lived relative to the process, so hitting this is rare; when it
+/// happens we just drop everything rather than implement a full LRU.
+const MAX_SESSIONS: usize = 64;
+
+fn session_state(session_id: u64) -> Arc<SessionState> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    if !sessions.contains_key(&session_id) && sessions.len() >= MAX_SESSIONS {
+        sessions.clear();
+    }
+    sessions
+        .entry(session_id)
+        .or_insert_with(|| {
+            Arc::new(SessionState {
+                generation: AtomicU64::new(0),
+                cancelled: AtomicBool::new(false),
+            })
+        })
+        .clone()
+}
+
+/// Marks `session_id`'s in-flight `fuzzy_match`, if any, as cancelled so it
+/// aborts on its next check instead of running to completion for a result
+/// Vim no longer cares about, then evicts the session's entry since there is
+/// nothing left to cancel once that in-flight call (if any) observes it.
+#[pyfunction]
+fn cancel_match(session_id: u64) {
+    if let Some(state) = SESSIONS.lock().unwrap().remove(&session_id) {
+        state.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
 /// Filter the candidates given query using the fzy algorithm
 #[pyfunction]
 fn fuzzy_match(
@@ -74,26 +320,80 @@ fn fuzzy_match(
     winwidth: usize,
     enable_icon: bool,
     line_splitter: String,
+    match_file_name_first: bool,
+    max_results: usize,
+    session_id: u64,
+    generation: u64,
 ) -> PyResult<(MatchedIndicesInBatch, LinesInBatch, TruncatedMapInfo)> {
+    let state = session_state(session_id);
+    state.generation.fetch_max(generation, Ordering::SeqCst);
+    // A fresh call always supersedes whatever was cancelled before it.
+    state.cancelled.store(false, Ordering::SeqCst);
+
     let fzy_matcher = get_appropriate_matcher(&Algo::Fzy, &line_splitter.into());
-    let matcher: Box<dyn Fn(&str) -> MatcherResult> = if query.contains(' ') {
-        Box::new(|line: &str| substr_scorer(query, line))
+    let is_extended_query = |q: &str| {
+        q.contains(' ')
+            || q.starts_with('\'')
+            || q.starts_with('^')
+            || q.starts_with('!')
+            || q.ends_with('$')
+    };
+    let matcher: Box<dyn Fn(&str) -> MatcherResult + Sync> = if is_extended_query(query) {
+        Box::new(move |line: &str| {
+            if match_file_name_first {
+                path_aware_substr_match(query, line)
+            } else {
+                substr_scorer(query, line)
+            }
+        })
     } else {
         Box::new(|line: &str| {
             if enable_icon {
                 // " " is 4 bytes, but the offset of highlight is 2.
-                fzy_matcher(&line[4..], query).map(|(score, indices)| {
-                    (score as f64, indices.into_iter().map(|x| x + 4).collect())
-                })
+                let result = if match_file_name_first {
+                    path_aware_fzy_match(&fzy_matcher, &line[4..], query)
+                } else {
+                    fzy_matcher(&line[4..], query).map(|(score, indices)| (score as f64, indices))
+                };
+                result.map(|(score, indices)| (score, indices.into_iter().map(|x| x + 4).collect()))
+            } else if match_file_name_first {
+                path_aware_fzy_match(&fzy_matcher, line, query)
             } else {
                 fzy_matcher(line, query).map(|(score, indices)| (score as f64, indices))
             }
         })
     };
 
-    let mut ranked = candidates
+    // Build one bounded min-heap per rayon worker so a provider streaming
+    // tens of thousands of lines only ever keeps `max_results` of them
+    // in memory at a time, then merge the per-thread heaps into one.
+    //
+    // Each step also checks whether a newer query has superseded this one
+    // (or `cancel_match` was called) and, if so, stops matching further
+    // candidates instead of burning CPU on an already-obsolete request.
+    let merged = candidates
+        .into_par_iter()
+        .fold(BinaryHeap::new, |mut heap, line| {
+            if state.cancelled.load(Ordering::Relaxed)
+                || state.generation.load(Ordering::Relaxed) != generation
+            {
+                return heap;
+            }
+            if let Some((score, indices)) = matcher(&line) {
+                push_bounded(&mut heap, ScoredLine { score, line, indices }, max_results);
+            }
+            heap
+        })
+        .reduce(BinaryHeap::new, |mut merged, local| {
+            for Reverse(item) in local {
+                push_bounded(&mut merged, item, max_results);
+            }
+            merged
+        });
+
+    let mut ranked = merged
         .into_iter()
-        .filter_map(|line| matcher(&line).map(|(score, indices)| (line, score, indices)))
+        .map(|Reverse(ScoredLine { score, line, indices })| (line, score, indices))
         .collect::<Vec<_>>();
 
     ranked.sort_unstable_by(|(_, v1, _), (_, v2, _)| v2.partial_cmp(v1).unwrap());
@@ -123,10 +423,37 @@ fn fuzzy_match(
 #[pymodule]
 fn fuzzymatch_rs(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_wrapped(wrap_pyfunction!(fuzzy_match))?;
+    m.add_wrapped(wrap_pyfunction!(cancel_match))?;
 
     Ok(())
 }
 
+#[test]
+fn test_extended_query_operators() {
+    // `!` rejects a candidate containing the negated term.
+    assert_eq!(substr_scorer("foo !bar", "foo baz"), substr_scorer("foo", "foo baz"));
+    assert_eq!(substr_scorer("foo !bar", "foo bar"), None);
+
+    // `^` anchors the term to the start of the haystack.
+    assert!(substr_scorer("^foo", "foobar").is_some());
+    assert_eq!(substr_scorer("^foo", "xfoobar"), None);
+
+    // `^` anchors to the true start of the haystack, not to wherever an
+    // earlier term's match left off.
+    assert_eq!(substr_scorer("foo ^bar", "xxxfoo barish"), None);
+    assert!(substr_scorer("^foo bar", "foo bar").is_some());
+
+    // `$` anchors the term to the end of the haystack.
+    assert!(substr_scorer("bar$", "foobar").is_some());
+    assert_eq!(substr_scorer("bar$", "foobarx"), None);
+
+    // A leading `'` is an explicit substring match, same as the default.
+    assert_eq!(
+        substr_scorer("'foo", "foobar").map(|(_, pos)| pos),
+        substr_scorer("foo", "foobar").map(|(_, pos)| pos)
+    );
+}
+
 #[test]
 fn py_and_rs_subscore_should_work() {
     use pyo3::{prelude::*, types::PyModule};
@@ -156,12 +483,105 @@ fn py_and_rs_subscore_should_work() {
     }
 }
 
+#[test]
+fn test_max_results_bounds_output() {
+    let lines = vec![
+        "foobar".to_string(),
+        "foo".to_string(),
+        "fooo".to_string(),
+        "foob".to_string(),
+    ];
+    let query = "foo";
+    let (_, filtered, _) =
+        fuzzy_match(query, lines, 80, false, "Full".to_string(), false, 2, 1, 1).unwrap();
+    assert_eq!(filtered.len(), 2);
+}
+
+#[test]
+fn test_cancel_match_cancels_in_flight_state_and_evicts_entry() {
+    let session_id = 42;
+    // Simulates an in-flight `fuzzy_match` holding on to its own `Arc`.
+    let in_flight = session_state(session_id);
+
+    cancel_match(session_id);
+    assert!(in_flight.cancelled.load(Ordering::SeqCst));
+
+    // The entry is evicted once cancelled, so the next lookup for the same
+    // session_id starts over from a fresh, uncancelled state rather than
+    // leaking the old one forever.
+    let fresh = session_state(session_id);
+    assert!(!Arc::ptr_eq(&in_flight, &fresh));
+    assert!(!fresh.cancelled.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_session_state_caps_map_size() {
+    for session_id in 1000..1000 + MAX_SESSIONS as u64 + 1 {
+        session_state(session_id);
+    }
+    assert!(SESSIONS.lock().unwrap().len() <= MAX_SESSIONS);
+}
+
+#[test]
+fn test_fuzzy_match_bumps_generation_and_clears_cancellation() {
+    let lines = vec!["foo".to_string(), "foobar".to_string()];
+    let session_id = 43;
+    cancel_match(session_id);
+
+    let (_, filtered, _) =
+        fuzzy_match("foo", lines, 80, false, "Full".to_string(), false, 0, session_id, 7).unwrap();
+
+    // A fresh call clears any earlier cancellation and wins the race.
+    assert_eq!(filtered.len(), 2);
+    let state = session_state(session_id);
+    assert_eq!(state.generation.load(Ordering::SeqCst), 7);
+    assert!(!state.cancelled.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_generation_cannot_be_rewound_by_a_stale_call() {
+    // Simulates two in-flight calls (generation 5 and 6) where the older
+    // one's own bookkeeping store happens to run last: `fetch_max` must
+    // keep the higher generation instead of letting the stale one win.
+    let session_id = 44;
+    let state = session_state(session_id);
+    state.generation.fetch_max(6, Ordering::SeqCst);
+    state.generation.fetch_max(5, Ordering::SeqCst);
+    assert_eq!(state.generation.load(Ordering::SeqCst), 6);
+}
+
+#[test]
+fn test_match_file_name_first_prioritizes_basename() {
+    let lines = vec![
+        "src/confusing/other.rs".to_string(),
+        "src/config.rs".to_string(),
+    ];
+    let query = "conf";
+    let (_, filtered, _) =
+        fuzzy_match(query, lines, 80, false, "Full".to_string(), true, 0, 2, 1).unwrap();
+    assert_eq!(filtered[0], "src/config.rs");
+}
+
+#[test]
+fn test_match_file_name_first_applies_to_extended_queries() {
+    // `match_file_name_first` must also kick in for queries that route
+    // through `substr_scorer` (multi-word queries, or ones using a
+    // fzf-style modifier), not just plain single-word fzy queries.
+    let lines = vec![
+        "src/confusing/other.rs".to_string(),
+        "src/config.rs".to_string(),
+    ];
+    let (_, filtered, _) =
+        fuzzy_match("co nf", lines, 80, false, "Full".to_string(), true, 0, 4, 1).unwrap();
+    assert_eq!(filtered[0], "src/config.rs");
+}
+
 #[test]
 fn test_skip_icon() {
     let lines = vec![" .dependabot/config.yml".into(), " .editorconfig".into()];
     let query = "con";
     println!(
         "ret: {:#?}",
-        fuzzy_match(query, lines, 62, true, "Full".to_string())
+        fuzzy_match(query, lines, 62, true, "Full".to_string(), false, 0, 3, 1)
     );
 }